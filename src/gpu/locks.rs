@@ -1,9 +1,16 @@
 use fs2::FileExt;
+use lazy_static::lazy_static;
 use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::fs::File;
+use std::future::Future;
+use std::io;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const GPU_LOCK_NAME: &str = "bellman.gpu.lock";
 const PRIORITY_LOCK_NAME: &str = "bellman.priority.lock";
 fn tmp_path(filename: &str) -> PathBuf {
     let mut p = std::env::temp_dir();
@@ -11,49 +18,540 @@ fn tmp_path(filename: &str) -> PathBuf {
     p
 }
 
-/// `GPULock` prevents two kernel objects to be instantiated simultaneously.
+/// Path of the small sentinel file a `GPULock` holder writes its pid into
+/// while the slot is held, so the next acquirer can tell whether the
+/// previous holder exited cleanly or crashed mid-kernel.
+fn sentinel_path(device_index: u64) -> PathBuf {
+    tmp_path(&format!("bellman.gpu.{}.sentinel", device_index))
+}
+
+fn read_sentinel(device_index: u64) -> Option<String> {
+    std::fs::read_to_string(sentinel_path(device_index))
+        .ok()
+        .filter(|s| !s.is_empty())
+}
+
+fn write_sentinel(device_index: u64) -> io::Result<()> {
+    std::fs::write(
+        sentinel_path(device_index),
+        format!("in use by pid {}", std::process::id()),
+    )
+}
+
+fn clear_sentinel(device_index: u64) -> io::Result<()> {
+    std::fs::write(sentinel_path(device_index), "")
+}
+
+/// Number of interchangeable GPU devices `GPUPool::default()` hands out
+/// slots for. Override with `BELLMAN_NUM_GPUS` on machines with more than
+/// one device; defaults to `1` so single-GPU behavior is unchanged.
+fn num_gpu_slots() -> u64 {
+    std::env::var("BELLMAN_NUM_GPUS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Path of device `device_index`'s own lock file. Each device gets a
+/// separate file (rather than one shared file with byte-range locks) so
+/// that `fs2`'s `flock`-based locking, which is scoped per open file
+/// description, gives each `GPULock` independent exclusion: acquiring one
+/// device's lock can never be satisfied by, or accidentally release, a
+/// lock held on another device or by another guard in the same process.
+///
+/// This is a deliberate deviation from one shared lock file with POSIX
+/// `fcntl` byte ranges per slot: `fcntl` record locks are per-*process*, so
+/// a second in-process guard would silently "acquire" an already-held
+/// range, and closing any fd on the file drops every lock the process
+/// holds on it, including ranges owned by other live guards. `flock` is
+/// per-open-file-description and avoids both problems, at the cost of one
+/// file per device instead of one file per pool.
+fn gpu_slot_path(device_index: u64) -> PathBuf {
+    tmp_path(&format!("bellman.gpu.{}.lock", device_index))
+}
+
+lazy_static! {
+    /// Device index -> unix timestamp the slot was acquired at, for
+    /// watchdogs that want to find the oldest outstanding GPU lock.
+    static ref HELD_SLOTS: Mutex<HashMap<u64, u64>> = Mutex::new(HashMap::new());
+
+    /// `GpuLockFut` id -> its current `Waker`. Keyed (rather than a plain
+    /// `VecDeque<Waker>`) so a future re-polled multiple times while still
+    /// pending replaces its own entry instead of piling up duplicates, and
+    /// so `Drop` can remove exactly its own waiter instead of evicting an
+    /// arbitrary one.
+    static ref GPU_WAITERS: Mutex<HashMap<u64, Waker>> = Mutex::new(HashMap::new());
+}
+
+fn next_gpu_waiter_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Wakes one parked `GpuLockFut`, if any, so it re-polls the pool now that a
+/// slot may be free. Called whenever a `GPULock` is dropped. (A waiting
+/// future that is itself dropped before being granted hands its wakeup on
+/// to another waiter in `GpuLockFut::drop` instead of calling this.)
+fn wake_next_gpu_waiter() {
+    let next = GPU_WAITERS.lock().unwrap().keys().next().copied();
+    if let Some(id) = next {
+        if let Some(waker) = GPU_WAITERS.lock().unwrap().remove(&id) {
+            waker.wake();
+        }
+    }
+}
+
+/// Wakes every parked `GpuLockFut` so it re-probes the pool. `GPULock::drop`
+/// only notifies same-process waiters directly, but the device a future is
+/// waiting on is often held by a *different* prover process; that process
+/// has no way to reach into our `GPU_WAITERS` when it releases its lock. A
+/// periodic sweep is what actually lets `acquire_async` make progress
+/// across processes instead of hanging until something in this process
+/// happens to call `wake_next_gpu_waiter`. Entries aren't removed here:
+/// each woken future either resolves (and removes itself) or stays pending
+/// and re-registers the same id on its next poll.
+fn wake_all_gpu_waiters() {
+    let wakers: Vec<Waker> = GPU_WAITERS.lock().unwrap().values().cloned().collect();
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// Starts the background re-poll sweep the first time any task parks on a
+/// `GpuLockFut`, if it isn't already running. The ticker stops itself once
+/// `GPU_WAITERS` goes empty rather than looping for the rest of the
+/// process's life, and a later call restarts it if a new task parks.
+fn ensure_gpu_waiter_ticker() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+    if RUNNING
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(Duration::from_millis(50));
+            wake_all_gpu_waiters();
+            if GPU_WAITERS.lock().unwrap().is_empty() {
+                RUNNING.store(false, Ordering::SeqCst);
+                // A waiter may have parked (and seen RUNNING still true,
+                // so skipped spawning its own ticker) between the check
+                // above and the store just now; if so, keep going
+                // ourselves instead of leaving it un-ticked.
+                if GPU_WAITERS.lock().unwrap().is_empty() {
+                    return;
+                }
+                RUNNING.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+}
+
+/// `GPUPool` models a machine with several interchangeable GPU devices.
+/// Each device owns its own lock file (`bellman.gpu.{i}.lock`), flocked
+/// exclusively for the duration the device is in use. Acquiring a slot is a
+/// non-blocking `try_lock_exclusive` on each device's file in turn; the
+/// first free one wins.
+#[derive(Debug, Clone, Copy)]
+pub struct GPUPool {
+    num_devices: u64,
+}
+
+impl GPUPool {
+    pub fn new(num_devices: u64) -> GPUPool {
+        GPUPool { num_devices }
+    }
+
+    /// Acquire the first free device slot, falling back to a blocking wait
+    /// on device 0 if every slot is currently taken. Returns
+    /// `Err(GPUError::LockPoisoned(lock))` rather than panicking if the
+    /// slot's sentinel shows its previous holder crashed mid-kernel; the
+    /// guard is still usable via `clear_poison()`.
+    pub fn acquire(&self) -> GPUResult<GPULock> {
+        debug!("Acquiring GPU slot...");
+        if let Some(result) = self.try_acquire() {
+            return result;
+        }
+        let f = File::create(gpu_slot_path(0))?;
+        f.lock_exclusive()?;
+        debug!("GPU slot 0 acquired after waiting!");
+        GPULock::new(f, 0)
+    }
+
+    /// Non-blocking probe of every device slot; `None` if all are currently
+    /// held elsewhere, so the caller can park instead of waiting. Each
+    /// device's file is opened fresh per probe, but since every probe that
+    /// doesn't win the lock drops its (unlocked) fd immediately, that can't
+    /// disturb a lock another guard is holding on a different open file
+    /// description of the same device file.
+    pub fn try_acquire(&self) -> Option<GPUResult<GPULock>> {
+        for device_index in 0..self.num_devices {
+            let f = match File::create(gpu_slot_path(device_index)) {
+                Ok(f) => f,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if f.try_lock_exclusive().is_ok() {
+                debug!("GPU slot {} acquired!", device_index);
+                return Some(GPULock::new(f, device_index));
+            }
+        }
+        None
+    }
+
+    /// Async counterpart of `acquire`: resolves once a slot frees up instead
+    /// of blocking the calling thread, so a small executor can multiplex
+    /// many proofs while they all wait on the same GPU.
+    pub fn acquire_async(&self) -> GpuLockFut {
+        GpuLockFut::new(*self)
+    }
+
+    /// Polls this pool for a free device slot with exponential backoff until
+    /// `timeout` elapses, returning `GPUError::AcquireTimeout` on expiry
+    /// instead of waiting forever. `GPULock::lock_timeout` is the
+    /// `GPUPool::default()`-bound convenience wrapper most callers want.
+    pub fn acquire_timeout(&self, timeout: Duration) -> GPUResult<GPULock> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(5);
+        loop {
+            if let Some(result) = self.try_acquire() {
+                return match result {
+                    Err(GPUError::LockPoisoned(lock)) => Ok(lock.clear_poison()),
+                    other => other,
+                };
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(GPUError::AcquireTimeout);
+            }
+            std::thread::sleep(backoff.min(remaining));
+            backoff = (backoff * 2).min(Duration::from_millis(200));
+        }
+    }
+}
+
+impl Default for GPUPool {
+    fn default() -> GPUPool {
+        GPUPool::new(num_gpu_slots())
+    }
+}
+
+/// Future returned by `GPUPool::acquire_async`. Modeled on a futures-aware
+/// mutex: each poll re-probes the pool with a non-blocking `try_acquire`,
+/// and on contention parks the task's `Waker` under its own id in
+/// `GPU_WAITERS` instead of spinning.
+pub struct GpuLockFut {
+    pool: GPUPool,
+    id: u64,
+    parked: bool,
+}
+
+impl GpuLockFut {
+    fn new(pool: GPUPool) -> GpuLockFut {
+        GpuLockFut {
+            pool,
+            id: next_gpu_waiter_id(),
+            parked: false,
+        }
+    }
+}
+
+impl Future for GpuLockFut {
+    type Output = GPUResult<GPULock>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.pool.try_acquire() {
+            Some(result) => {
+                if self.parked {
+                    GPU_WAITERS.lock().unwrap().remove(&self.id);
+                    self.parked = false;
+                }
+                Poll::Ready(result)
+            }
+            None => {
+                ensure_gpu_waiter_ticker();
+                // Insert, not push: a future repolled while still pending
+                // must replace its own entry rather than add a second one.
+                GPU_WAITERS
+                    .lock()
+                    .unwrap()
+                    .insert(self.id, cx.waker().clone());
+                self.parked = true;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for GpuLockFut {
+    fn drop(&mut self) {
+        if self.parked {
+            let mut waiters = GPU_WAITERS.lock().unwrap();
+            waiters.remove(&self.id);
+            // We may have been the waiter a `GPULock::drop` just woke up to
+            // retry; since we're being cancelled instead of polled again,
+            // pass that wakeup on to another waiter so it isn't lost.
+            if let Some(waker) = waiters.values().next().cloned() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// `GPULock` prevents two kernel objects from being instantiated on the
+/// same device simultaneously. It names the device index its slot of the
+/// `GPUPool` corresponds to, so callers can point `create_fft_kernel`/
+/// `create_multiexp_kernel` at that specific GPU.
 #[derive(Debug)]
-pub struct GPULock(File);
+pub struct GPULock {
+    file: File,
+    device_index: u64,
+}
 impl GPULock {
-    pub fn lock() -> GPULock {
-        debug!("Acquiring GPU lock...");
-        let f = File::create(tmp_path(GPU_LOCK_NAME)).unwrap();
-        f.lock_exclusive().unwrap();
-        debug!("GPU lock acquired!");
-        GPULock(f)
+    /// Finishes taking a slot that's already locked at the OS level: records
+    /// it for `oldest_held`, checks the device's sentinel for a crashed
+    /// previous holder, and (re)writes the sentinel with our own pid.
+    fn new(file: File, device_index: u64) -> GPUResult<GPULock> {
+        HELD_SLOTS.lock().unwrap().insert(device_index, now_secs());
+        let lock = GPULock { file, device_index };
+        let prior_holder = read_sentinel(device_index);
+        let _ = write_sentinel(device_index);
+        if let Some(sentinel) = prior_holder {
+            warn!(
+                "GPU slot {} was left '{}' by a previous holder that didn't exit cleanly",
+                device_index, sentinel
+            );
+            return Err(GPUError::LockPoisoned(lock));
+        }
+        Ok(lock)
+    }
+
+    /// Acquire a slot from `GPUPool::default()` (sized by
+    /// `BELLMAN_NUM_GPUS`, a single device when that is unset).
+    /// Kept around so existing call sites that just want "the" GPU don't
+    /// need to know about `GPUPool`.
+    pub fn lock() -> GPUResult<GPULock> {
+        GPUPool::default().acquire()
+    }
+
+    /// Non-blocking: `None` if every device slot is currently held
+    /// elsewhere, rather than waiting for one to free up.
+    pub fn try_lock() -> Option<GPULock> {
+        match GPUPool::default().try_acquire() {
+            Some(Ok(lock)) => Some(lock),
+            Some(Err(GPUError::LockPoisoned(lock))) => Some(lock.clear_poison()),
+            Some(Err(e)) => {
+                warn!("Couldn't acquire GPU lock: {}", e);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Polls for a free device slot with exponential backoff until
+    /// `timeout` elapses, returning `GPUError::AcquireTimeout` on expiry
+    /// instead of waiting forever.
+    pub fn lock_timeout(timeout: Duration) -> GPUResult<GPULock> {
+        GPUPool::default().acquire_timeout(timeout)
+    }
+
+    /// Mirrors `std::sync::Mutex`'s poison recovery: a caller that has
+    /// satisfied itself the GPU is actually fine can pull the guard back
+    /// out of a `GPUError::LockPoisoned` and keep going instead of
+    /// aborting the proof.
+    pub fn clear_poison(self) -> GPULock {
+        self
+    }
+
+    /// The device index this guard locked.
+    pub fn device_index(&self) -> u64 {
+        self.device_index
+    }
+
+    /// The `(device_index, acquired_at)` of the longest-held outstanding
+    /// slot, for watchdogs that want to kill stuck provers.
+    pub fn oldest_held() -> Option<(u64, u64)> {
+        HELD_SLOTS
+            .lock()
+            .unwrap()
+            .iter()
+            .min_by_key(|&(_, acquired_at)| *acquired_at)
+            .map(|(&device_index, &acquired_at)| (device_index, acquired_at))
     }
 }
 impl Drop for GPULock {
     fn drop(&mut self) {
-        debug!("GPU lock released!");
+        let _ = &self.file;
+        let _ = clear_sentinel(self.device_index);
+        HELD_SLOTS.lock().unwrap().remove(&self.device_index);
+        debug!("GPU slot {} released!", self.device_index);
+        wake_next_gpu_waiter();
+    }
+}
+
+const PRIORITY_TABLE_NAME: &str = "bellman.priority.table";
+
+/// One waiter's entry in the shared priority table: `level` is its priority
+/// (higher wins), `seq` is the order it registered in (lower goes first
+/// among equal levels), `pid` is the process that registered it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PriorityRecord {
+    level: u8,
+    seq: u64,
+    pid: u32,
+}
+
+/// True iff `record` should be served before a waiter at `level` that
+/// registered as `seq`: a strictly higher level always goes first, and an
+/// equal level goes first iff it registered earlier. Only the exact
+/// `(level, seq)` pair of the waiter asking is excluded — *not* every
+/// record sharing its pid, since two threads/tasks of the same process can
+/// each be a distinct waiter and must still be ordered against each other.
+fn outranks(record: &PriorityRecord, level: u8, seq: u64) -> bool {
+    !(record.level == level && record.seq == seq)
+        && (record.level > level || (record.level == level && record.seq < seq))
+}
+
+/// Runs `f` with the priority table file exclusively `flock`ed, so the
+/// read-modify-write of registering/removing a waiter is atomic across
+/// processes.
+fn with_priority_table<T>(f: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(tmp_path(PRIORITY_TABLE_NAME))?;
+    file.lock_exclusive()?;
+    let result = f(&mut file);
+    let _ = file.unlock();
+    result
+}
+
+fn read_priority_records(file: &mut File) -> io::Result<Vec<PriorityRecord>> {
+    use std::io::{Read, Seek, SeekFrom};
+    file.seek(SeekFrom::Start(0))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            Some(PriorityRecord {
+                level: fields.next()?.parse().ok()?,
+                seq: fields.next()?.parse().ok()?,
+                pid: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+fn write_priority_records(file: &mut File, records: &[PriorityRecord]) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    for r in records {
+        writeln!(file, "{} {} {}", r.level, r.seq, r.pid)?;
     }
+    Ok(())
 }
 
-/// `PrioriyLock` is like a flag. When acquired, it means a high-priority process
-/// needs to acquire the GPU really soon. Acquiring the `PriorityLock` is like
-/// signaling all other processes to release their `GPULock`s.
-/// Only one process can have the `PriorityLock` at a time.
+/// `PriorityLock` is like a flag. When acquired at a given level, it means a
+/// process of that priority needs to acquire the GPU really soon. Acquiring
+/// the `PriorityLock` is like signaling all lower-priority processes to
+/// release their `GPULock`s. Unlike a single global flag, waiters of equal
+/// level are served in the order they registered rather than racing.
 #[derive(Debug)]
-pub struct PriorityLock(File);
+pub struct PriorityLock {
+    file: File,
+    level: u8,
+    seq: u64,
+    pid: u32,
+}
 impl PriorityLock {
-    pub fn lock() -> PriorityLock {
-        debug!("Acquiring priority lock...");
-        let f = File::create(tmp_path(PRIORITY_LOCK_NAME)).unwrap();
-        f.lock_exclusive().unwrap();
+    /// Equivalent to `lock_with_level(u8::MAX)`, for callers that just want
+    /// "the" priority lock like before multi-level support existed.
+    pub fn lock() -> GPUResult<PriorityLock> {
+        PriorityLock::lock_with_level(u8::MAX)
+    }
+
+    /// Registers as a `level`-priority waiter in the shared table, then
+    /// waits until every waiter that outranks us (strictly higher level, or
+    /// the same level registered with a smaller `seq`) has gone first
+    /// before taking the underlying exclusive lock. The table record stays
+    /// in place for as long as the returned guard is held (it's removed in
+    /// `Drop`), so `should_break` can see that a higher-level job has *the*
+    /// lock, not just that one is still waiting for it. FIFO ordering among
+    /// equal-level waiters holds because an earlier waiter's record keeps
+    /// outranking later ones until its guard is dropped; the `flock` on
+    /// `PRIORITY_LOCK_NAME`, taken after the loop below, additionally makes
+    /// a higher-level waiter wait for a lower-level guard still held.
+    pub fn lock_with_level(level: u8) -> GPUResult<PriorityLock> {
+        debug!("Acquiring priority lock at level {}...", level);
+        let pid = std::process::id();
+        let seq = with_priority_table(|file| {
+            let mut records = read_priority_records(file)?;
+            let seq = records.iter().map(|r| r.seq).max().map_or(0, |s| s + 1);
+            records.push(PriorityRecord { level, seq, pid });
+            write_priority_records(file, &records)?;
+            Ok(seq)
+        })?;
+
+        loop {
+            let outranked = with_priority_table(|file| {
+                let records = read_priority_records(file)?;
+                Ok(records.iter().any(|r| outranks(r, level, seq)))
+            })?;
+            if !outranked {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let f = File::create(tmp_path(PRIORITY_LOCK_NAME))?;
+        f.lock_exclusive()?;
         debug!("Priority lock acquired!");
-        PriorityLock(f)
+        Ok(PriorityLock {
+            file: f,
+            level,
+            seq,
+            pid,
+        })
     }
-    pub fn should_break(priority: bool) -> bool {
-        !priority
-            && File::create(tmp_path(PRIORITY_LOCK_NAME))
-                .unwrap()
-                .try_lock_exclusive()
-                .is_err()
+
+    /// True iff a waiter with a strictly higher level than `my_level` is
+    /// currently registered *or holding* the priority lock, i.e. a more
+    /// important job wants the GPU or already has it.
+    pub fn should_break(my_level: u8) -> bool {
+        match with_priority_table(|file| read_priority_records(file)) {
+            Ok(records) => records.iter().any(|r| r.level > my_level),
+            Err(e) => {
+                warn!("Couldn't check priority table, assuming no break: {}", e);
+                false
+            }
+        }
     }
 }
 impl Drop for PriorityLock {
     fn drop(&mut self) {
-        debug!("Priority lock released!");
+        let _ = &self.file;
+        let (pid, seq) = (self.pid, self.seq);
+        if let Err(e) = with_priority_table(|file| {
+            let mut records = read_priority_records(file)?;
+            records.retain(|r| !(r.pid == pid && r.seq == seq));
+            write_priority_records(file, &records)
+        }) {
+            warn!("Couldn't remove priority record on release: {}", e);
+        }
+        debug!(
+            "Priority lock released! (level {}, seq {})",
+            self.level, self.seq
+        );
     }
 }
 
@@ -65,12 +563,12 @@ use crate::multiexp::create_multiexp_kernel;
 use paired::Engine;
 
 macro_rules! locked_kernel {
-    ($class:ident, $kern:ident, $func:ident) => {
+    ($class:ident, $kern:ident, $func:ident, $with_async:ident) => {
         pub struct $class<E>
         where
             E: Engine,
         {
-            priority: bool,
+            priority_level: u8,
             kernel: Option<$kern<E>>,
         }
 
@@ -78,9 +576,9 @@ macro_rules! locked_kernel {
         where
             E: Engine,
         {
-            pub fn new(priority: bool) -> $class<E> {
+            pub fn new(priority_level: u8) -> $class<E> {
                 $class::<E> {
-                    priority,
+                    priority_level,
                     kernel: None,
                 }
             }
@@ -95,11 +593,69 @@ macro_rules! locked_kernel {
             where
                 F: FnOnce(&mut $kern<E>) -> GPUResult<R>,
             {
-                if PriorityLock::should_break(self.priority) {
+                if PriorityLock::should_break(self.priority_level) {
+                    self.free();
+                } else if self.kernel.is_none() {
+                    info!("GPU is available!");
+                    let gpu_lock = match GPUPool::default().acquire() {
+                        Ok(lock) => Some(lock),
+                        Err(GPUError::LockPoisoned(lock)) => {
+                            warn!(
+                                "Recovered a poisoned GPU lock; a previous holder may have left the GPU in an inconsistent state"
+                            );
+                            Some(lock.clear_poison())
+                        }
+                        Err(e) => {
+                            warn!("Couldn't acquire GPU lock: {}", e);
+                            None
+                        }
+                    };
+                    self.kernel = gpu_lock.and_then(|lock| {
+                        let device_index = lock.device_index();
+                        $func::<E>(device_index, lock, self.priority_level)
+                    });
+                }
+
+                if let Some(ref mut k) = self.kernel {
+                    let res = f(k);
+                    if let Err(GPUError::GPUTaken) = res {
+                        self.free();
+                    }
+                    res
+                } else {
+                    Err(GPUError::GPUTaken)
+                }
+            }
+
+            /// Like `with`, but bounds how long to wait for the GPU: once
+            /// `timeout` elapses without a slot freeing up, falls back to
+            /// the same CPU code path used when `kernel` is `None`, rather
+            /// than blocking the prover indefinitely.
+            pub fn with_timeout<F, R>(&mut self, timeout: Duration, f: F) -> GPUResult<R>
+            where
+                F: FnOnce(&mut $kern<E>) -> GPUResult<R>,
+            {
+                if PriorityLock::should_break(self.priority_level) {
                     self.free();
                 } else if self.kernel.is_none() {
                     info!("GPU is available!");
-                    self.kernel = $func::<E>(self.priority);
+                    match GPULock::lock_timeout(timeout) {
+                        Ok(lock) => {
+                            let device_index = lock.device_index();
+                            self.kernel = $func::<E>(device_index, lock, self.priority_level);
+                        }
+                        Err(GPUError::AcquireTimeout) => {
+                            warn!(
+                                "Timed out after {:?} waiting for the GPU, falling back to CPU",
+                                timeout
+                            );
+                            return Err(GPUError::GPUTaken);
+                        }
+                        Err(e) => {
+                            warn!("Couldn't acquire GPU lock: {}", e);
+                            return Err(GPUError::GPUTaken);
+                        }
+                    }
                 }
 
                 if let Some(ref mut k) = self.kernel {
@@ -112,9 +668,190 @@ macro_rules! locked_kernel {
                     Err(GPUError::GPUTaken)
                 }
             }
+
+            /// Async counterpart of `with`: `.await`s the GPU instead of
+            /// blocking the calling thread, then runs `f` on a blocking
+            /// pool so the executor thread stays free while the kernel
+            /// runs.
+            pub async fn $with_async<F, R>(&mut self, f: F) -> GPUResult<R>
+            where
+                F: FnOnce(&mut $kern<E>) -> GPUResult<R> + Send + 'static,
+                R: Send + 'static,
+                $kern<E>: Send + 'static,
+            {
+                if PriorityLock::should_break(self.priority_level) {
+                    self.free();
+                } else if self.kernel.is_none() {
+                    info!("GPU is available!");
+                    let gpu_lock = match GPUPool::default().acquire_async().await {
+                        Ok(lock) => Some(lock),
+                        Err(GPUError::LockPoisoned(lock)) => {
+                            warn!(
+                                "Recovered a poisoned GPU lock; a previous holder may have left the GPU in an inconsistent state"
+                            );
+                            Some(lock.clear_poison())
+                        }
+                        Err(e) => {
+                            warn!("Couldn't acquire GPU lock: {}", e);
+                            None
+                        }
+                    };
+                    self.kernel = gpu_lock.and_then(|lock| {
+                        let device_index = lock.device_index();
+                        $func::<E>(device_index, lock, self.priority_level)
+                    });
+                }
+
+                if let Some(mut k) = self.kernel.take() {
+                    let (k, res) = tokio::task::spawn_blocking(move || {
+                        let res = f(&mut k);
+                        (k, res)
+                    })
+                    .await
+                    .expect("blocking GPU task panicked");
+                    self.kernel = Some(k);
+                    if let Err(GPUError::GPUTaken) = res {
+                        self.free();
+                    }
+                    res
+                } else {
+                    Err(GPUError::GPUTaken)
+                }
+            }
         }
     };
 }
 
-locked_kernel!(LockedFFTKernel, FFTKernel, create_fft_kernel);
-locked_kernel!(LockedMultiexpKernel, MultiexpKernel, create_multiexp_kernel);
\ No newline at end of file
+locked_kernel!(LockedFFTKernel, FFTKernel, create_fft_kernel, with_fft_async);
+locked_kernel!(
+    LockedMultiexpKernel,
+    MultiexpKernel,
+    create_multiexp_kernel,
+    with_async
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{RawWaker, RawWakerVTable};
+
+    #[test]
+    fn outranks_strictly_higher_level_wins() {
+        let higher = PriorityRecord { level: 5, seq: 0, pid: 1 };
+        assert!(outranks(&higher, 1, 10));
+    }
+
+    #[test]
+    fn outranks_earlier_seq_at_equal_level_wins() {
+        let earlier = PriorityRecord { level: 3, seq: 1, pid: 1 };
+        assert!(outranks(&earlier, 3, 10));
+    }
+
+    #[test]
+    fn outranks_later_seq_at_equal_level_does_not_win() {
+        let later = PriorityRecord { level: 3, seq: 20, pid: 1 };
+        assert!(!outranks(&later, 3, 10));
+    }
+
+    #[test]
+    fn outranks_never_considers_own_entry() {
+        let own = PriorityRecord { level: 3, seq: 10, pid: 42 };
+        assert!(!outranks(&own, 3, 10));
+    }
+
+    #[test]
+    fn outranks_does_not_spare_other_entries_from_the_same_pid() {
+        // A second waiter registered by the same process (e.g. another
+        // thread/task) must still outrank us if it otherwise would; only
+        // our own (level, seq) is exempt, not our whole pid.
+        let same_pid_higher = PriorityRecord {
+            level: 9,
+            seq: 11,
+            pid: std::process::id(),
+        };
+        assert!(outranks(&same_pid_higher, 3, 10));
+    }
+
+    #[test]
+    fn sentinel_round_trip_detects_unclean_exit() {
+        let device_index = 9_000_001;
+        let _ = clear_sentinel(device_index);
+        assert_eq!(read_sentinel(device_index), None);
+
+        write_sentinel(device_index).unwrap();
+        assert!(read_sentinel(device_index).is_some());
+
+        clear_sentinel(device_index).unwrap();
+        assert_eq!(read_sentinel(device_index), None);
+    }
+
+    #[test]
+    fn priority_lock_record_persists_while_held_and_is_removed_on_drop() {
+        let pid = std::process::id();
+        let level = 250;
+        let guard = PriorityLock::lock_with_level(level).unwrap();
+
+        let held = with_priority_table(|file| read_priority_records(file)).unwrap();
+        assert!(held.iter().any(|r| r.pid == pid && r.level == level));
+        assert!(PriorityLock::should_break(level - 1));
+
+        drop(guard);
+
+        let after = with_priority_table(|file| read_priority_records(file)).unwrap();
+        assert!(!after.iter().any(|r| r.pid == pid && r.level == level));
+    }
+
+    #[test]
+    fn acquire_timeout_expires_when_pool_has_no_slots() {
+        let result = GPUPool::new(0).acquire_timeout(Duration::from_millis(20));
+        assert!(matches!(result, Err(GPUError::AcquireTimeout)));
+    }
+
+    fn noop_waker(woken: Arc<AtomicBool>) -> Waker {
+        fn clone(ptr: *const ()) -> RawWaker {
+            unsafe { Arc::increment_strong_count(ptr as *const AtomicBool) };
+            RawWaker::new(ptr, &VTABLE)
+        }
+        fn wake(ptr: *const ()) {
+            let flag = unsafe { Arc::from_raw(ptr as *const AtomicBool) };
+            flag.store(true, Ordering::SeqCst);
+        }
+        fn wake_by_ref(ptr: *const ()) {
+            let flag = unsafe { &*(ptr as *const AtomicBool) };
+            flag.store(true, Ordering::SeqCst);
+        }
+        fn drop_fn(ptr: *const ()) {
+            unsafe { drop(Arc::from_raw(ptr as *const AtomicBool)) };
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_fn);
+        let ptr = Arc::into_raw(woken) as *const ();
+        unsafe { Waker::from_raw(RawWaker::new(ptr, &VTABLE)) }
+    }
+
+    #[test]
+    fn dropping_a_parked_future_hands_off_its_wakeup() {
+        let other_woken = Arc::new(AtomicBool::new(false));
+        let other_id = next_gpu_waiter_id();
+        GPU_WAITERS
+            .lock()
+            .unwrap()
+            .insert(other_id, noop_waker(other_woken.clone()));
+
+        let fut = GpuLockFut {
+            pool: GPUPool::new(0),
+            id: next_gpu_waiter_id(),
+            parked: true,
+        };
+        GPU_WAITERS
+            .lock()
+            .unwrap()
+            .insert(fut.id, noop_waker(Arc::new(AtomicBool::new(false))));
+
+        drop(fut);
+
+        assert!(other_woken.load(Ordering::SeqCst));
+        GPU_WAITERS.lock().unwrap().remove(&other_id);
+    }
+}